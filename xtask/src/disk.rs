@@ -0,0 +1,331 @@
+//! Disk image creation, factored out so new backends (qcow2, and
+//! eventually VMDK/VHD) can be added without touching the QEMU
+//! invocation logic.
+
+use clap::ValueEnum;
+use fatfs::{FatType, FileSystem, FormatVolumeOptions, FsOptions};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// On-disk image format, selected with `--disk-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DiskFormat {
+    /// Flat, sparse raw image (the default).
+    Raw,
+    /// Copy-on-write qcow2 image, created via `qemu-img`.
+    Qcow2,
+}
+
+impl std::fmt::Display for DiskFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DiskFormat::Raw => write!(f, "raw"),
+            DiskFormat::Qcow2 => write!(f, "qcow2"),
+        }
+    }
+}
+
+/// Default size for a disk image that doesn't specify one explicitly.
+pub const DEFAULT_DISK_SIZE: u64 = 0x400_0000; // 64MB
+
+/// Default logical sector size for a disk image.
+pub const DEFAULT_SECTOR_SIZE: u16 = 512;
+
+/// A `--disk <path>[:size][:sectorsize]` specification.
+///
+/// `size` accepts an optional `K`/`M`/`G` suffix (binary units); bare
+/// numbers are bytes.
+#[derive(Clone, Debug)]
+pub struct DiskSpec {
+    pub path: PathBuf,
+    pub size: u64,
+    pub sector_size: u16,
+}
+
+impl std::str::FromStr for DiskSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut fields = spec.split(':');
+        let path = fields
+            .next()
+            .filter(|s| !s.is_empty())
+            .ok_or_else(|| format!("--disk '{spec}' is missing a path"))?;
+        let size = fields
+            .next()
+            .filter(|s| !s.is_empty())
+            .map(parse_size)
+            .transpose()?
+            .unwrap_or(DEFAULT_DISK_SIZE);
+        let sector_size = fields
+            .next()
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let n = s
+                    .parse::<u16>()
+                    .map_err(|e| format!("invalid sector size '{s}' in --disk '{spec}': {e}"))?;
+                if !matches!(n, 512 | 1024 | 2048 | 4096) {
+                    return Err(format!(
+                        "invalid sector size '{s}' in --disk '{spec}': must be one of \
+                         512, 1024, 2048, 4096"
+                    ));
+                }
+                Ok(n)
+            })
+            .transpose()?
+            .unwrap_or(DEFAULT_SECTOR_SIZE);
+        if fields.next().is_some() {
+            return Err(format!(
+                "--disk '{spec}' has too many ':'-separated fields \
+                 (expected path[:size][:sectorsize])"
+            ));
+        }
+        Ok(DiskSpec {
+            path: PathBuf::from(path),
+            size,
+            sector_size,
+        })
+    }
+}
+
+/// Parse a size with an optional binary-unit suffix (`K`, `M`, `G`).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .map(|n| n * multiplier)
+        .map_err(|e| format!("invalid size '{s}': {e}"))
+}
+
+/// A disk image to be created and attached to QEMU.
+pub struct DiskImage {
+    pub path: PathBuf,
+    pub format: DiskFormat,
+}
+
+impl DiskImage {
+    pub fn new(path: PathBuf, format: DiskFormat) -> Self {
+        Self { path, format }
+    }
+
+    /// Create the image on disk, formatted as FAT32 with the given logical
+    /// `sector_size` and optionally staged with `rootfs`. For `Raw` this
+    /// writes the FAT32 filesystem directly into the file; for `Qcow2` the
+    /// FAT32 filesystem is built in a raw temp file first, then converted
+    /// with `qemu-img`.
+    pub fn create(&self, size: u64, sector_size: u16, rootfs: Option<&Path>) {
+        match self.format {
+            DiskFormat::Raw => {
+                create_raw(&self.path, size, sector_size, rootfs);
+                println!(
+                    "Created disk image: {} ({}MB, {}B sectors, FAT32, raw)",
+                    self.path.display(),
+                    size / (1024 * 1024),
+                    sector_size
+                );
+            }
+            DiskFormat::Qcow2 => {
+                let raw_path = self.path.with_extension("raw.tmp");
+                create_raw(&raw_path, size, sector_size, rootfs);
+
+                let status = Command::new("qemu-img")
+                    .args([
+                        "convert",
+                        "-f",
+                        "raw",
+                        "-O",
+                        "qcow2",
+                        raw_path.to_str().unwrap(),
+                        self.path.to_str().unwrap(),
+                    ])
+                    .status()
+                    .unwrap_or_else(|e| {
+                        eprintln!("Error: failed to execute qemu-img: {}", e);
+                        process::exit(1);
+                    });
+                if !status.success() {
+                    eprintln!("Error: qemu-img convert failed");
+                    process::exit(status.code().unwrap_or(1));
+                }
+                std::fs::remove_file(&raw_path).ok();
+
+                println!(
+                    "Created disk image: {} ({}MB, {}B sectors, qcow2)",
+                    self.path.display(),
+                    size / (1024 * 1024),
+                    sector_size
+                );
+            }
+        }
+    }
+
+    /// The `-drive` value QEMU should use to attach this image.
+    pub fn qemu_drive_arg(&self, id: &str) -> String {
+        let format = match self.format {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+        };
+        format!(
+            "file={},format={},if=none,id={}",
+            self.path.display(),
+            format,
+            id
+        )
+    }
+}
+
+/// Create a disk image formatted as a real FAT32 filesystem with the given
+/// logical `sector_size`.
+///
+/// If `rootfs` is given, its contents are copied into the image's root
+/// directory (recursively) so the guest has real files to read back,
+/// rather than a single magic string.
+fn create_raw(path: &Path, size: u64, sector_size: u16, rootfs: Option<&Path>) {
+    let mut f = File::create(path).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create disk image {}: {}", path.display(), e);
+        process::exit(1);
+    });
+    f.set_len(size).unwrap_or_else(|e| {
+        eprintln!(
+            "Error: failed to set {} to {} bytes: {}",
+            path.display(),
+            size,
+            e
+        );
+        process::exit(1);
+    });
+
+    fatfs::format_volume(
+        &mut f,
+        FormatVolumeOptions::new()
+            .fat_type(FatType::Fat32)
+            .bytes_per_sector(sector_size),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Error: failed to format {} as FAT32: {}", path.display(), e);
+        process::exit(1);
+    });
+
+    let fs = FileSystem::new(&mut f, FsOptions::new()).unwrap_or_else(|e| {
+        eprintln!("Error: failed to open FAT32 filesystem: {}", e);
+        process::exit(1);
+    });
+
+    if let Some(rootfs) = rootfs {
+        copy_dir_into(rootfs, &fs.root_dir()).unwrap_or_else(|e| {
+            eprintln!(
+                "Error: failed to stage {} onto disk image: {}",
+                rootfs.display(),
+                e
+            );
+            process::exit(1);
+        });
+        println!("Staged rootfs: {} -> {}", rootfs.display(), path.display());
+    }
+}
+
+/// Recursively copy a host directory into a FAT directory.
+///
+/// Symlinks are skipped rather than followed: `Path::is_dir` resolves
+/// symlinks, so without this a cycle under `host_dir` would recurse
+/// unboundedly, and a symlink pointing outside `host_dir` would get
+/// silently staged into the image.
+fn copy_dir_into<IO: fatfs::ReadWriteSeek>(
+    host_dir: &Path,
+    fat_dir: &fatfs::Dir<IO>,
+) -> io::Result<()> {
+    for entry in std::fs::read_dir(host_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_symlink() {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name.to_str().unwrap_or_else(|| {
+            eprintln!("Error: non-UTF-8 file name under {}", host_dir.display());
+            process::exit(1);
+        });
+        let path = entry.path();
+
+        if path.is_dir() {
+            let sub = fat_dir.create_dir(name)?;
+            copy_dir_into(&path, &sub)?;
+        } else {
+            let mut src = File::open(&path)?;
+            let mut dst = fat_dir.create_file(name)?;
+            io::copy(&mut src, &mut dst)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn disk_spec_path_only() {
+        let spec = DiskSpec::from_str("disk.img").unwrap();
+        assert_eq!(spec.path, PathBuf::from("disk.img"));
+        assert_eq!(spec.size, DEFAULT_DISK_SIZE);
+        assert_eq!(spec.sector_size, DEFAULT_SECTOR_SIZE);
+    }
+
+    #[test]
+    fn disk_spec_path_and_size() {
+        let spec = DiskSpec::from_str("disk.img:64M").unwrap();
+        assert_eq!(spec.path, PathBuf::from("disk.img"));
+        assert_eq!(spec.size, 64 * 1024 * 1024);
+        assert_eq!(spec.sector_size, DEFAULT_SECTOR_SIZE);
+    }
+
+    #[test]
+    fn disk_spec_empty_size_falls_back_to_default() {
+        let spec = DiskSpec::from_str("disk.img::4096").unwrap();
+        assert_eq!(spec.path, PathBuf::from("disk.img"));
+        assert_eq!(spec.size, DEFAULT_DISK_SIZE);
+        assert_eq!(spec.sector_size, 4096);
+    }
+
+    #[test]
+    fn disk_spec_path_size_and_sector_size() {
+        let spec = DiskSpec::from_str("disk.img:64M:4096").unwrap();
+        assert_eq!(spec.path, PathBuf::from("disk.img"));
+        assert_eq!(spec.size, 64 * 1024 * 1024);
+        assert_eq!(spec.sector_size, 4096);
+    }
+
+    #[test]
+    fn disk_spec_rejects_empty_path() {
+        assert!(DiskSpec::from_str("").is_err());
+        assert!(DiskSpec::from_str(":64M").is_err());
+    }
+
+    #[test]
+    fn disk_spec_rejects_invalid_size() {
+        assert!(DiskSpec::from_str("disk.img:notasize").is_err());
+    }
+
+    #[test]
+    fn disk_spec_rejects_invalid_sector_size() {
+        assert!(DiskSpec::from_str("disk.img:64M:notanumber").is_err());
+    }
+
+    #[test]
+    fn disk_spec_rejects_non_power_of_two_sector_size() {
+        assert!(DiskSpec::from_str("disk.img:64M:0").is_err());
+        assert!(DiskSpec::from_str("disk.img:64M:1000").is_err());
+    }
+
+    #[test]
+    fn disk_spec_rejects_too_many_fields() {
+        assert!(DiskSpec::from_str("disk.img:64M:4096:extra").is_err());
+    }
+}
@@ -1,8 +1,13 @@
+mod disk;
+
 use clap::{Parser, Subcommand};
-use std::fs::File;
-use std::io::Write;
+use disk::{DiskFormat, DiskImage, DiskSpec, DEFAULT_DISK_SIZE, DEFAULT_SECTOR_SIZE};
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::{self, Command};
+use std::process::{self, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 /// ArceOS readblk multi-architecture build & run tool
 #[derive(Parser)]
@@ -15,6 +20,9 @@ struct Cli {
 #[derive(Subcommand)]
 enum Cmd {
     /// Build the kernel for a given architecture
+    ///
+    /// `--rootfs` isn't offered here: `Build` never creates a disk image,
+    /// so there's nothing to stage it onto. See `Run`.
     Build {
         /// Target architecture: riscv64, aarch64, x86_64, loongarch64
         #[arg(long, default_value = "riscv64")]
@@ -25,14 +33,75 @@ enum Cmd {
         /// Target architecture: riscv64, aarch64, x86_64, loongarch64
         #[arg(long, default_value = "riscv64")]
         arch: String,
+        /// Host directory to stage onto the first disk image's FAT filesystem
+        #[arg(long)]
+        rootfs: Option<PathBuf>,
+        /// Disk image format: raw, qcow2
+        #[arg(long, value_enum, default_value_t = DiskFormat::Raw)]
+        disk_format: DiskFormat,
+        /// Attach a VirtIO block device: path[:size][:sectorsize] (repeatable).
+        /// Defaults to one 64MB, 512-byte-sector disk at target/disk.img
+        /// (target/disk.qcow2 with `--disk-format qcow2`).
+        #[arg(long = "disk")]
+        disks: Vec<DiskSpec>,
+        #[command(flatten)]
+        qemu: QemuOpts,
+    },
+    /// Build and boot every architecture headless, asserting the readblk
+    /// app read back a known marker file staged onto the disk image
+    Test {
+        /// Seconds to wait for each architecture to boot before killing it
+        #[arg(long, default_value_t = 15)]
+        timeout: u64,
+        #[command(flatten)]
+        qemu: QemuOpts,
     },
 }
 
+/// QEMU options shared by `Run` and `Test` so runs stay reproducible
+/// without editing source.
+#[derive(clap::Args)]
+struct QemuOpts {
+    /// Memory size passed to QEMU's -m
+    #[arg(long, default_value = "128M")]
+    mem: String,
+    /// Number of vCPUs passed to QEMU's -smp
+    #[arg(long, default_value = "1")]
+    smp: String,
+    /// Override the per-architecture default -cpu
+    #[arg(long)]
+    cpu: Option<String>,
+    /// Extra argument passed through to QEMU verbatim (repeatable)
+    #[arg(long = "qemu-arg")]
+    qemu_arg: Vec<String>,
+}
+
+impl QemuOpts {
+    fn config(&self) -> QemuConfig<'_> {
+        QemuConfig {
+            mem: &self.mem,
+            smp: &self.smp,
+            cpu: self.cpu.as_deref(),
+            extra_args: &self.qemu_arg,
+        }
+    }
+}
+
 #[allow(dead_code)]
 struct ArchInfo {
     target: &'static str,
     platform: &'static str,
     objcopy_arch: &'static str,
+    /// QEMU `-machine` value.
+    machine: &'static str,
+    /// Default `-cpu` value, overridable with `--cpu`. `None` means QEMU's
+    /// own default for the machine.
+    default_cpu: Option<&'static str>,
+    /// `-bios` value, if this architecture boots through one.
+    bios: Option<&'static str>,
+    /// Whether QEMU is given the ELF directly (`-kernel <elf>`) instead of
+    /// the objcopy'd raw binary.
+    kernel_is_elf: bool,
 }
 
 fn arch_info(arch: &str) -> ArchInfo {
@@ -41,21 +110,37 @@ fn arch_info(arch: &str) -> ArchInfo {
             target: "riscv64gc-unknown-none-elf",
             platform: "riscv64-qemu-virt",
             objcopy_arch: "riscv64",
+            machine: "virt",
+            default_cpu: None,
+            bios: Some("default"),
+            kernel_is_elf: false,
         },
         "aarch64" => ArchInfo {
             target: "aarch64-unknown-none-softfloat",
             platform: "aarch64-qemu-virt",
             objcopy_arch: "aarch64",
+            machine: "virt",
+            default_cpu: Some("cortex-a72"),
+            bios: None,
+            kernel_is_elf: false,
         },
         "x86_64" => ArchInfo {
             target: "x86_64-unknown-none",
             platform: "x86-pc",
             objcopy_arch: "x86_64",
+            machine: "q35",
+            default_cpu: None,
+            bios: None,
+            kernel_is_elf: true,
         },
         "loongarch64" => ArchInfo {
             target: "loongarch64-unknown-none",
             platform: "loongarch64-qemu-virt",
             objcopy_arch: "loongarch64",
+            machine: "virt",
+            default_cpu: None,
+            bios: None,
+            kernel_is_elf: false,
         },
         _ => {
             eprintln!(
@@ -93,47 +178,6 @@ fn install_config(root: &Path, arch: &str) {
     println!("Installed config: {} -> .axconfig.toml", src.display());
 }
 
-/// Create a 64MB disk image with a FAT-like boot sector header.
-///
-/// The first 512-byte block contains:
-/// - Bytes 0..3: JMP SHORT 0x3C; NOP (x86 boot jump)
-/// - Bytes 3..11: OEM ID "mkfs.fat" (8 bytes, valid UTF-8)
-///
-/// This allows the application to read bytes 3..11 and parse them
-/// as a UTF-8 string to verify block device I/O.
-fn create_disk_image(path: &Path) {
-    const DISK_SIZE: usize = 0x400_0000; // 64MB
-
-    let mut boot_sector = vec![0u8; 512];
-
-    // FAT boot sector: 3-byte jump instruction
-    boot_sector[0] = 0xEB; // JMP SHORT
-    boot_sector[1] = 0x3C; // offset
-    boot_sector[2] = 0x90; // NOP
-
-    // OEM ID at bytes 3..11
-    let oem = b"mkfs.fat";
-    boot_sector[3..11].copy_from_slice(oem);
-
-    // Bytes per sector (512 = 0x0200, little-endian)
-    boot_sector[11] = 0x00;
-    boot_sector[12] = 0x02;
-
-    let mut f = File::create(path).unwrap_or_else(|e| {
-        eprintln!("Error: failed to create disk image {}: {}", path.display(), e);
-        process::exit(1);
-    });
-    f.write_all(&boot_sector).unwrap();
-    // Extend to full 64MB (sparse file)
-    f.set_len(DISK_SIZE as u64).unwrap();
-
-    println!(
-        "Created disk image: {} ({}MB)",
-        path.display(),
-        DISK_SIZE / (1024 * 1024)
-    );
-}
-
 /// Run cargo build for the target architecture.
 fn do_build(root: &Path, info: &ArchInfo) {
     let manifest = root.join("Cargo.toml");
@@ -175,71 +219,87 @@ fn do_objcopy(elf: &Path, bin: &Path, objcopy_arch: &str) {
     }
 }
 
-/// Run the kernel image in QEMU with a VirtIO block device.
-fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path) {
-    let mem = "128M";
-    let smp = "1";
+/// Machine/CPU/kernel arguments that differ per architecture. `cpu`
+/// overrides `info.default_cpu` when given (`--cpu`).
+fn arch_qemu_args(info: &ArchInfo, elf: &Path, bin: &Path, cpu: Option<&str>) -> Vec<String> {
+    let mut args = vec!["-machine".into(), info.machine.to_string()];
 
-    let qemu = format!("qemu-system-{arch}");
+    if let Some(cpu) = cpu.or(info.default_cpu) {
+        args.extend(["-cpu".into(), cpu.to_string()]);
+    }
+    if let Some(bios) = info.bios {
+        args.extend(["-bios".into(), bios.to_string()]);
+    }
 
+    let kernel = if info.kernel_is_elf { elf } else { bin };
+    args.extend(["-kernel".into(), kernel.to_str().unwrap().to_string()]);
+
+    args
+}
+
+/// Attach each disk image as its own VirtIO PCI block device: `disk0`,
+/// `disk1`, ...
+fn disk_qemu_args(disks: &[DiskImage]) -> Vec<String> {
+    let mut args = Vec::new();
+    for (i, disk) in disks.iter().enumerate() {
+        let id = format!("disk{i}");
+        args.extend([
+            "-drive".into(),
+            disk.qemu_drive_arg(&id),
+            "-device".into(),
+            format!("virtio-blk-pci,drive={id}"),
+        ]);
+    }
+    args
+}
+
+/// Per-run QEMU configuration, threaded through from CLI flags so runs
+/// stay reproducible without editing source.
+struct QemuConfig<'a> {
+    mem: &'a str,
+    smp: &'a str,
+    cpu: Option<&'a str>,
+    extra_args: &'a [String],
+}
+
+/// Assemble the QEMU arguments shared by `Run` and `Test`: `-m`/`-smp`,
+/// the caller's `display_args` (e.g. `-nographic`, or `-display none
+/// -serial stdio` for a captured test run), then the arch/disk/extra-arg
+/// plumbing. Keeping this in one place means `do_run_qemu` and
+/// `test_one_arch` can't drift on how disks or `--qemu-arg` are threaded
+/// through.
+fn qemu_args(
+    display_args: &[&str],
+    info: &ArchInfo,
+    elf: &Path,
+    bin: &Path,
+    disks: &[DiskImage],
+    cfg: &QemuConfig,
+) -> Vec<String> {
     let mut args: Vec<String> = vec![
         "-m".into(),
-        mem.into(),
+        cfg.mem.into(),
         "-smp".into(),
-        smp.into(),
-        "-nographic".into(),
+        cfg.smp.into(),
     ];
+    args.extend(display_args.iter().map(|s| s.to_string()));
+    args.extend(arch_qemu_args(info, elf, bin, cfg.cpu));
+    args.extend(disk_qemu_args(disks));
+    args.extend(cfg.extra_args.iter().cloned());
+    args
+}
 
-    match arch {
-        "riscv64" => {
-            args.extend([
-                "-machine".into(),
-                "virt".into(),
-                "-bios".into(),
-                "default".into(),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-            ]);
-        }
-        "aarch64" => {
-            args.extend([
-                "-cpu".into(),
-                "cortex-a72".into(),
-                "-machine".into(),
-                "virt".into(),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-            ]);
-        }
-        "x86_64" => {
-            args.extend([
-                "-machine".into(),
-                "q35".into(),
-                "-kernel".into(),
-                elf.to_str().unwrap().into(),
-            ]);
-        }
-        "loongarch64" => {
-            args.extend([
-                "-machine".into(),
-                "virt".into(),
-                "-kernel".into(),
-                bin.to_str().unwrap().into(),
-            ]);
-        }
-        _ => unreachable!(),
-    }
-
-    // Attach the disk image as a VirtIO PCI block device.
-    args.extend([
-        "-drive".into(),
-        format!(
-            "file={},format=raw,if=none,id=disk0",
-            disk.display()
-        ),
-        "-device".into(),
-        "virtio-blk-pci,drive=disk0".into(),
-    ]);
+/// Run the kernel image in QEMU with one or more VirtIO block devices.
+fn do_run_qemu(
+    arch: &str,
+    info: &ArchInfo,
+    elf: &Path,
+    bin: &Path,
+    disks: &[DiskImage],
+    cfg: &QemuConfig,
+) {
+    let qemu = format!("qemu-system-{arch}");
+    let args = qemu_args(&["-nographic"], info, elf, bin, disks, cfg);
 
     println!("Running: {} {}", qemu, args.join(" "));
     let status = Command::new(&qemu)
@@ -254,6 +314,121 @@ fn do_run_qemu(arch: &str, elf: &Path, bin: &Path, disk: &Path) {
     }
 }
 
+/// Name and contents of a file staged onto the test disk's `--rootfs` so
+/// the boot test has a verifiable signal: the FAT32 BPB's OEM field isn't
+/// configurable (fatfs hardcodes it to "MSWIN4.1"), so the test instead
+/// checks that the readblk app read this file back over serial.
+const TEST_MARKER_FILE: &str = "READBLK_TEST_MARKER.TXT";
+const TEST_MARKER_CONTENTS: &str = "xtask-boot-test-ok";
+
+/// Build and boot `arch` headless, capturing its serial output and
+/// asserting it contains `TEST_MARKER_CONTENTS`. The QEMU process is
+/// killed if it hasn't exited within `timeout_secs`, so a hung boot
+/// fails the test instead of blocking the whole run.
+fn test_one_arch(root: &Path, arch: &str, timeout_secs: u64, cfg: &QemuConfig) -> bool {
+    println!("=== Testing {arch} ===");
+
+    let info = arch_info(arch);
+    install_config(root, arch);
+    do_build(root, &info);
+
+    let elf = root
+        .join("target")
+        .join(info.target)
+        .join("release")
+        .join("arceos-readblk");
+    let bin = elf.with_extension("bin");
+
+    let rootfs = root.join("target").join(format!("test-rootfs-{arch}"));
+    std::fs::create_dir_all(&rootfs).unwrap_or_else(|e| {
+        eprintln!("Error: failed to create {}: {}", rootfs.display(), e);
+        process::exit(1);
+    });
+    std::fs::write(rootfs.join(TEST_MARKER_FILE), TEST_MARKER_CONTENTS).unwrap_or_else(|e| {
+        eprintln!("Error: failed to write test marker file: {}", e);
+        process::exit(1);
+    });
+
+    let disk = DiskImage::new(
+        root.join("target").join(format!("disk-test-{arch}.img")),
+        DiskFormat::Raw,
+    );
+    disk.create(DEFAULT_DISK_SIZE, DEFAULT_SECTOR_SIZE, Some(&rootfs));
+
+    if arch != "x86_64" {
+        do_objcopy(&elf, &bin, info.objcopy_arch);
+    }
+
+    let qemu = format!("qemu-system-{arch}");
+    let args = qemu_args(
+        &["-display", "none", "-serial", "stdio"],
+        &info,
+        &elf,
+        &bin,
+        std::slice::from_ref(&disk),
+        cfg,
+    );
+
+    println!("Running: {} {}", qemu, args.join(" "));
+    let mut child = Command::new(&qemu)
+        .args(&args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap_or_else(|e| {
+            eprintln!("Error: failed to launch {}: {}", qemu, e);
+            process::exit(1);
+        });
+
+    let mut stdout = child.stdout.take().expect("child stdout was piped");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).ok();
+        tx.send(buf).ok();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(timeout_secs);
+    let timed_out = loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break false,
+            Ok(None) if Instant::now() >= deadline => {
+                eprintln!(
+                    "{arch}: timed out after {timeout_secs}s, killing {qemu}"
+                );
+                child.kill().ok();
+                break true;
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(100)),
+            Err(e) => {
+                eprintln!("Error: failed to wait on {}: {}", qemu, e);
+                break true;
+            }
+        }
+    };
+    child.wait().ok();
+
+    let output = rx
+        .recv_timeout(Duration::from_secs(5))
+        .unwrap_or_default();
+    let serial = String::from_utf8_lossy(&output);
+    let passed = !timed_out && serial.contains(TEST_MARKER_CONTENTS);
+
+    println!("--- {arch}: {} ---", if passed { "PASS" } else { "FAIL" });
+    passed
+}
+
+/// Build and boot every architecture headless, returning `false` if any
+/// architecture's serial output didn't contain the staged marker file's
+/// contents.
+fn do_test(root: &Path, timeout_secs: u64, cfg: &QemuConfig) -> bool {
+    const ARCHES: [&str; 4] = ["riscv64", "aarch64", "x86_64", "loongarch64"];
+    ARCHES
+        .iter()
+        .map(|arch| test_one_arch(root, arch, timeout_secs, cfg))
+        .fold(true, |all_passed, passed| all_passed && passed)
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -266,7 +441,13 @@ fn main() {
             do_build(&root, &info);
             println!("Build complete for {arch} ({})", info.target);
         }
-        Cmd::Run { ref arch } => {
+        Cmd::Run {
+            ref arch,
+            ref rootfs,
+            disk_format,
+            ref disks,
+            ref qemu,
+        } => {
             let info = arch_info(arch);
             install_config(&root, arch);
             do_build(&root, &info);
@@ -278,16 +459,48 @@ fn main() {
                 .join("arceos-readblk");
             let bin = elf.with_extension("bin");
 
-            // Create disk image
-            let disk = root.join("target").join("disk.img");
-            create_disk_image(&disk);
+            // Default to a single disk at target/disk.img (target/disk.qcow2
+            // for qcow2) when none are given.
+            let default_ext = match disk_format {
+                DiskFormat::Raw => "img",
+                DiskFormat::Qcow2 => "qcow2",
+            };
+            let specs: Vec<DiskSpec> = if disks.is_empty() {
+                vec![DiskSpec {
+                    path: root.join("target").join(format!("disk.{default_ext}")),
+                    size: DEFAULT_DISK_SIZE,
+                    sector_size: DEFAULT_SECTOR_SIZE,
+                }]
+            } else {
+                disks.clone()
+            };
+
+            // Create and format each disk image; only the first is staged
+            // with --rootfs.
+            let images: Vec<DiskImage> = specs
+                .into_iter()
+                .enumerate()
+                .map(|(i, spec)| {
+                    let image = DiskImage::new(spec.path, disk_format);
+                    let rootfs = if i == 0 { rootfs.as_deref() } else { None };
+                    image.create(spec.size, spec.sector_size, rootfs);
+                    image
+                })
+                .collect();
 
             // objcopy for non-x86_64 architectures
             if arch != "x86_64" {
                 do_objcopy(&elf, &bin, info.objcopy_arch);
             }
 
-            do_run_qemu(arch, &elf, &bin, &disk);
+            do_run_qemu(arch, &info, &elf, &bin, &images, &qemu.config());
+        }
+        Cmd::Test { timeout, ref qemu } => {
+            if !do_test(&root, timeout, &qemu.config()) {
+                eprintln!("Error: one or more architectures failed the boot test");
+                process::exit(1);
+            }
+            println!("All architectures passed");
         }
     }
 }